@@ -1,6 +1,9 @@
+use std::collections::{HashMap, HashSet};
+
 use serde::{Deserialize, Serialize};
 
 use super::{Expr, Operator};
+use crate::ast::Call;
 use crate::ast::ImportPattern;
 use crate::DeclId;
 use crate::{engine::StateWorkingSet, BlockId, Signature, Span, Type, VarId, IN_VARIABLE_ID};
@@ -13,6 +16,20 @@ pub struct Expression {
     pub custom_completion: Option<DeclId>,
 }
 
+/// What [`Expression::walk`]/[`Expression::walk_mut`] should do after visiting
+/// a node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WalkControl {
+    /// Keep going: descend into this node's children, then move on to its
+    /// siblings and the rest of the tree.
+    Continue,
+    /// Don't descend into this node's children, but otherwise keep going —
+    /// siblings and the rest of the tree are still visited.
+    SkipChildren,
+    /// Abort the walk entirely; nothing else is visited.
+    Stop,
+}
+
 impl Expression {
     pub fn garbage(span: Span) -> Expression {
         Expression {
@@ -116,313 +133,399 @@ impl Expression {
         }
     }
 
-    pub fn has_in_variable(&self, working_set: &StateWorkingSet) -> bool {
+    /// Visit this expression and everything nested within it in pre-order.
+    /// Returns `true` if the walk ran to the end, `false` if `f` returned
+    /// [`WalkControl::Stop`] somewhere. [`WalkControl::SkipChildren`] only
+    /// prunes the current node's children; it does not abort the rest of the
+    /// walk (siblings and ancestors' remaining children are still visited).
+    pub fn walk(
+        &self,
+        working_set: &StateWorkingSet,
+        f: &mut impl FnMut(&Expression) -> WalkControl,
+    ) -> bool {
+        match f(self) {
+            WalkControl::Stop => return false,
+            WalkControl::SkipChildren => return true,
+            WalkControl::Continue => {}
+        }
+
         match &self.expr {
-            Expr::BinaryOp(left, _, right) => {
-                left.has_in_variable(working_set) || right.has_in_variable(working_set)
+            Expr::BinaryOp(left, op, right) => {
+                left.walk(working_set, f) && op.walk(working_set, f) && right.walk(working_set, f)
             }
-            Expr::UnaryNot(expr) => expr.has_in_variable(working_set),
-            Expr::Block(block_id) => {
+            Expr::UnaryNot(expr) => expr.walk(working_set, f),
+            Expr::Block(block_id)
+            | Expr::RowCondition(block_id)
+            | Expr::Subexpression(block_id) => {
                 let block = working_set.get_block(*block_id);
 
-                if block.captures.contains(&IN_VARIABLE_ID) {
-                    return true;
-                }
-
-                if let Some(pipeline) = block.pipelines.get(0) {
-                    match pipeline.expressions.get(0) {
-                        Some(expr) => expr.has_in_variable(working_set),
-                        None => false,
+                for pipeline in &block.pipelines {
+                    for expr in &pipeline.expressions {
+                        if !expr.walk(working_set, f) {
+                            return false;
+                        }
                     }
-                } else {
-                    false
                 }
+                true
             }
-            Expr::Binary(_) => false,
-            Expr::Bool(_) => false,
             Expr::Call(call) => {
                 for positional in call.positional_iter() {
-                    if positional.has_in_variable(working_set) {
-                        return true;
+                    if !positional.walk(working_set, f) {
+                        return false;
                     }
                 }
                 for named in call.named_iter() {
                     if let Some(expr) = &named.2 {
-                        if expr.has_in_variable(working_set) {
-                            return true;
+                        if !expr.walk(working_set, f) {
+                            return false;
                         }
                     }
                 }
-                false
+                true
             }
-            Expr::CellPath(_) => false,
-            Expr::DateTime(_) => false,
             Expr::ExternalCall(head, args) => {
-                if head.has_in_variable(working_set) {
-                    return true;
+                if !head.walk(working_set, f) {
+                    return false;
                 }
                 for arg in args {
-                    if arg.has_in_variable(working_set) {
-                        return true;
+                    if !arg.walk(working_set, f) {
+                        return false;
                     }
                 }
-                false
+                true
             }
-            Expr::ImportPattern(_) => false,
-            Expr::Filepath(_) => false,
-            Expr::Directory(_) => false,
-            Expr::Float(_) => false,
-            Expr::FullCellPath(full_cell_path) => {
-                if full_cell_path.head.has_in_variable(working_set) {
-                    return true;
-                }
-                false
-            }
-            Expr::Garbage => false,
-            Expr::Nothing => false,
-            Expr::GlobPattern(_) => false,
-            Expr::Int(_) => false,
-            Expr::Keyword(_, _, expr) => expr.has_in_variable(working_set),
+            Expr::FullCellPath(full_cell_path) => full_cell_path.head.walk(working_set, f),
+            Expr::Keyword(_, _, expr) => expr.walk(working_set, f),
             Expr::List(list) => {
                 for l in list {
-                    if l.has_in_variable(working_set) {
-                        return true;
+                    if !l.walk(working_set, f) {
+                        return false;
                     }
                 }
-                false
+                true
             }
             Expr::StringInterpolation(items) => {
                 for i in items {
-                    if i.has_in_variable(working_set) {
-                        return true;
+                    if !i.walk(working_set, f) {
+                        return false;
                     }
                 }
-                false
+                true
             }
-            Expr::Operator(_) => false,
             Expr::Range(left, middle, right, ..) => {
-                if let Some(left) = &left {
-                    if left.has_in_variable(working_set) {
-                        return true;
+                if let Some(left) = left {
+                    if !left.walk(working_set, f) {
+                        return false;
                     }
                 }
-                if let Some(middle) = &middle {
-                    if middle.has_in_variable(working_set) {
-                        return true;
+                if let Some(middle) = middle {
+                    if !middle.walk(working_set, f) {
+                        return false;
                     }
                 }
-                if let Some(right) = &right {
-                    if right.has_in_variable(working_set) {
-                        return true;
+                if let Some(right) = right {
+                    if !right.walk(working_set, f) {
+                        return false;
                     }
                 }
-                false
+                true
             }
             Expr::Record(fields) => {
                 for (field_name, field_value) in fields {
-                    if field_name.has_in_variable(working_set) {
-                        return true;
-                    }
-                    if field_value.has_in_variable(working_set) {
-                        return true;
+                    if !field_name.walk(working_set, f) || !field_value.walk(working_set, f) {
+                        return false;
                     }
                 }
-                false
-            }
-            Expr::Signature(_) => false,
-            Expr::String(_) => false,
-            Expr::RowCondition(block_id) | Expr::Subexpression(block_id) => {
-                let block = working_set.get_block(*block_id);
-
-                if let Some(pipeline) = block.pipelines.get(0) {
-                    if let Some(expr) = pipeline.expressions.get(0) {
-                        expr.has_in_variable(working_set)
-                    } else {
-                        false
-                    }
-                } else {
-                    false
-                }
+                true
             }
             Expr::Table(headers, cells) => {
                 for header in headers {
-                    if header.has_in_variable(working_set) {
-                        return true;
+                    if !header.walk(working_set, f) {
+                        return false;
                     }
                 }
-
                 for row in cells {
                     for cell in row.iter() {
-                        if cell.has_in_variable(working_set) {
-                            return true;
+                        if !cell.walk(working_set, f) {
+                            return false;
                         }
                     }
                 }
-
-                false
+                true
             }
-
-            Expr::ValueWithUnit(expr, _) => expr.has_in_variable(working_set),
-            Expr::Var(var_id) => *var_id == IN_VARIABLE_ID,
-            Expr::VarDecl(_) => false,
+            Expr::ValueWithUnit(expr, _) => expr.walk(working_set, f),
+            Expr::Binary(_)
+            | Expr::Bool(_)
+            | Expr::CellPath(_)
+            | Expr::DateTime(_)
+            | Expr::ImportPattern(_)
+            | Expr::Filepath(_)
+            | Expr::Directory(_)
+            | Expr::Float(_)
+            | Expr::Garbage
+            | Expr::Nothing
+            | Expr::GlobPattern(_)
+            | Expr::Int(_)
+            | Expr::Operator(_)
+            | Expr::Signature(_)
+            | Expr::String(_)
+            | Expr::Var(_)
+            | Expr::VarDecl(_) => true,
         }
     }
 
-    pub fn replace_in_variable(&mut self, working_set: &mut StateWorkingSet, new_var_id: VarId) {
-        match &mut self.expr {
-            Expr::BinaryOp(left, _, right) => {
-                left.replace_in_variable(working_set, new_var_id);
-                right.replace_in_variable(working_set, new_var_id);
-            }
-            Expr::UnaryNot(expr) => {
-                expr.replace_in_variable(working_set, new_var_id);
-            }
-            Expr::Block(block_id) => {
-                let block = working_set.get_block(*block_id);
+    /// The mutable counterpart of [`walk`](Self::walk); same
+    /// stop-vs-skip-children contract, and the same `true`/`false` return
+    /// meaning.
+    pub fn walk_mut(
+        &mut self,
+        working_set: &mut StateWorkingSet,
+        f: &mut impl FnMut(&mut Expression) -> WalkControl,
+    ) -> bool {
+        let span = self.span;
 
-                let new_expr = if let Some(pipeline) = block.pipelines.get(0) {
-                    if let Some(expr) = pipeline.expressions.get(0) {
-                        let mut new_expr = expr.clone();
-                        new_expr.replace_in_variable(working_set, new_var_id);
-                        Some(new_expr)
-                    } else {
-                        None
-                    }
-                } else {
-                    None
-                };
+        match f(self) {
+            WalkControl::Stop => return false,
+            WalkControl::SkipChildren => return true,
+            WalkControl::Continue => {}
+        }
 
-                let block = working_set.get_block_mut(*block_id);
+        match &mut self.expr {
+            Expr::BinaryOp(left, op, right) => {
+                if !left.walk_mut(working_set, f) {
+                    return false;
+                }
+                if !op.walk_mut(working_set, f) {
+                    return false;
+                }
+                if !right.walk_mut(working_set, f) {
+                    return false;
+                }
+            }
+            Expr::UnaryNot(expr) => return expr.walk_mut(working_set, f),
+            Expr::Block(block_id)
+            | Expr::RowCondition(block_id)
+            | Expr::Subexpression(block_id) => {
+                // Mutate the block in place (via `get_block_mut`) rather than
+                // cloning the whole block and re-interning it under a new
+                // `block_id`: the latter would permanently grow the working
+                // set's block table with an orphaned entry on every pass that
+                // changes anything, which adds up in a long-lived session.
+                let block_id = *block_id;
+                let num_pipelines = working_set.get_block(block_id).pipelines.len();
 
-                if let Some(new_expr) = new_expr {
-                    if let Some(pipeline) = block.pipelines.get_mut(0) {
-                        if let Some(expr) = pipeline.expressions.get_mut(0) {
-                            *expr = new_expr
+                for p in 0..num_pipelines {
+                    let num_exprs = working_set.get_block(block_id).pipelines[p].expressions.len();
+                    for e in 0..num_exprs {
+                        let mut expr =
+                            working_set.get_block(block_id).pipelines[p].expressions[e].clone();
+                        let kept_going = expr.walk_mut(working_set, f);
+                        working_set.get_block_mut(block_id).pipelines[p].expressions[e] = expr;
+                        if !kept_going {
+                            return false;
                         }
                     }
                 }
 
-                block.captures = block
-                    .captures
+                let captures = working_set.get_block(block_id).captures.clone();
+                let mut stopped = false;
+                let new_captures = captures
                     .iter()
-                    .map(|x| if *x != IN_VARIABLE_ID { *x } else { new_var_id })
+                    .map(|var_id| {
+                        if stopped {
+                            return *var_id;
+                        }
+                        let mut var = Expression {
+                            expr: Expr::Var(*var_id),
+                            span,
+                            ty: Type::Any,
+                            custom_completion: None,
+                        };
+                        stopped = f(&mut var) == WalkControl::Stop;
+                        match var.expr {
+                            Expr::Var(new_id) => new_id,
+                            _ => *var_id,
+                        }
+                    })
                     .collect();
+                working_set.get_block_mut(block_id).captures = new_captures;
+                if stopped {
+                    return false;
+                }
             }
-            Expr::Binary(_) => {}
-            Expr::Bool(_) => {}
             Expr::Call(call) => {
                 for positional in call.positional_iter_mut() {
-                    positional.replace_in_variable(working_set, new_var_id);
+                    if !positional.walk_mut(working_set, f) {
+                        return false;
+                    }
                 }
                 for named in call.named_iter_mut() {
                     if let Some(expr) = &mut named.2 {
-                        expr.replace_in_variable(working_set, new_var_id)
+                        if !expr.walk_mut(working_set, f) {
+                            return false;
+                        }
                     }
                 }
             }
-            Expr::CellPath(_) => {}
-            Expr::DateTime(_) => {}
             Expr::ExternalCall(head, args) => {
-                head.replace_in_variable(working_set, new_var_id);
+                if !head.walk_mut(working_set, f) {
+                    return false;
+                }
                 for arg in args {
-                    arg.replace_in_variable(working_set, new_var_id)
+                    if !arg.walk_mut(working_set, f) {
+                        return false;
+                    }
                 }
             }
-            Expr::Filepath(_) => {}
-            Expr::Directory(_) => {}
-            Expr::Float(_) => {}
             Expr::FullCellPath(full_cell_path) => {
-                full_cell_path
-                    .head
-                    .replace_in_variable(working_set, new_var_id);
-            }
-            Expr::ImportPattern(_) => {}
-            Expr::Garbage => {}
-            Expr::Nothing => {}
-            Expr::GlobPattern(_) => {}
-            Expr::Int(_) => {}
-            Expr::Keyword(_, _, expr) => expr.replace_in_variable(working_set, new_var_id),
+                return full_cell_path.head.walk_mut(working_set, f)
+            }
+            Expr::Keyword(_, _, expr) => return expr.walk_mut(working_set, f),
             Expr::List(list) => {
                 for l in list {
-                    l.replace_in_variable(working_set, new_var_id)
+                    if !l.walk_mut(working_set, f) {
+                        return false;
+                    }
+                }
+            }
+            Expr::StringInterpolation(items) => {
+                for i in items {
+                    if !i.walk_mut(working_set, f) {
+                        return false;
+                    }
                 }
             }
-            Expr::Operator(_) => {}
             Expr::Range(left, middle, right, ..) => {
                 if let Some(left) = left {
-                    left.replace_in_variable(working_set, new_var_id)
+                    if !left.walk_mut(working_set, f) {
+                        return false;
+                    }
                 }
                 if let Some(middle) = middle {
-                    middle.replace_in_variable(working_set, new_var_id)
+                    if !middle.walk_mut(working_set, f) {
+                        return false;
+                    }
                 }
                 if let Some(right) = right {
-                    right.replace_in_variable(working_set, new_var_id)
+                    if !right.walk_mut(working_set, f) {
+                        return false;
+                    }
                 }
             }
             Expr::Record(fields) => {
                 for (field_name, field_value) in fields {
-                    field_name.replace_in_variable(working_set, new_var_id);
-                    field_value.replace_in_variable(working_set, new_var_id);
-                }
-            }
-            Expr::Signature(_) => {}
-            Expr::String(_) => {}
-            Expr::StringInterpolation(items) => {
-                for i in items {
-                    i.replace_in_variable(working_set, new_var_id)
-                }
-            }
-            Expr::RowCondition(block_id) | Expr::Subexpression(block_id) => {
-                let block = working_set.get_block(*block_id);
-
-                let new_expr = if let Some(pipeline) = block.pipelines.get(0) {
-                    if let Some(expr) = pipeline.expressions.get(0) {
-                        let mut new_expr = expr.clone();
-                        new_expr.replace_in_variable(working_set, new_var_id);
-                        Some(new_expr)
-                    } else {
-                        None
-                    }
-                } else {
-                    None
-                };
-
-                let block = working_set.get_block_mut(*block_id);
-
-                if let Some(new_expr) = new_expr {
-                    if let Some(pipeline) = block.pipelines.get_mut(0) {
-                        if let Some(expr) = pipeline.expressions.get_mut(0) {
-                            *expr = new_expr
-                        }
+                    if !field_name.walk_mut(working_set, f) || !field_value.walk_mut(working_set, f)
+                    {
+                        return false;
                     }
                 }
-
-                block.captures = block
-                    .captures
-                    .iter()
-                    .map(|x| if *x != IN_VARIABLE_ID { *x } else { new_var_id })
-                    .collect();
             }
             Expr::Table(headers, cells) => {
                 for header in headers {
-                    header.replace_in_variable(working_set, new_var_id)
+                    if !header.walk_mut(working_set, f) {
+                        return false;
+                    }
                 }
-
                 for row in cells {
                     for cell in row.iter_mut() {
-                        cell.replace_in_variable(working_set, new_var_id)
+                        if !cell.walk_mut(working_set, f) {
+                            return false;
+                        }
                     }
                 }
             }
+            Expr::ValueWithUnit(expr, _) => return expr.walk_mut(working_set, f),
+            Expr::Binary(_)
+            | Expr::Bool(_)
+            | Expr::CellPath(_)
+            | Expr::DateTime(_)
+            | Expr::ImportPattern(_)
+            | Expr::Filepath(_)
+            | Expr::Directory(_)
+            | Expr::Float(_)
+            | Expr::Garbage
+            | Expr::Nothing
+            | Expr::GlobPattern(_)
+            | Expr::Int(_)
+            | Expr::Operator(_)
+            | Expr::Signature(_)
+            | Expr::String(_)
+            | Expr::Var(_)
+            | Expr::VarDecl(_) => {}
+        }
+
+        true
+    }
 
-            Expr::ValueWithUnit(expr, _) => expr.replace_in_variable(working_set, new_var_id),
-            Expr::Var(x) => {
-                if *x == IN_VARIABLE_ID {
-                    *x = new_var_id
+    pub fn has_in_variable(&self, working_set: &StateWorkingSet) -> bool {
+        let mut found = false;
+        self.walk(working_set, &mut |expr| {
+            match &expr.expr {
+                Expr::Var(var_id) if *var_id == IN_VARIABLE_ID => found = true,
+                Expr::Block(block_id)
+                | Expr::RowCondition(block_id)
+                | Expr::Subexpression(block_id) => {
+                    if working_set
+                        .get_block(*block_id)
+                        .captures
+                        .contains(&IN_VARIABLE_ID)
+                    {
+                        found = true;
+                    }
                 }
+                _ => {}
             }
-            Expr::VarDecl(_) => {}
-        }
+            // Stop as soon as we know the answer instead of walking the rest
+            // of the tree for nothing.
+            if found {
+                WalkControl::Stop
+            } else {
+                WalkControl::Continue
+            }
+        });
+        found
+    }
+
+    pub fn replace_in_variable(&mut self, working_set: &mut StateWorkingSet, new_var_id: VarId) {
+        let mut map = HashMap::new();
+        map.insert(IN_VARIABLE_ID, new_var_id);
+        self.substitute(working_set, &map);
+    }
+
+    /// Every variable referenced by this expression but bound outside of it.
+    pub fn free_variables(&self, working_set: &StateWorkingSet) -> HashSet<VarId> {
+        let mut vars = HashSet::new();
+        self.walk(working_set, &mut |expr| match &expr.expr {
+            Expr::Var(var_id) => {
+                vars.insert(*var_id);
+                WalkControl::Continue
+            }
+            Expr::Block(block_id)
+            | Expr::RowCondition(block_id)
+            | Expr::Subexpression(block_id) => {
+                vars.extend(working_set.get_block(*block_id).captures.iter().copied());
+                // Don't descend into the block's own body (its captures
+                // already summarize what it pulls in from our scope), but
+                // this must not stop the walk: a `Call`/`List`/`Table`/etc.
+                // can have other arguments after a block that still need
+                // visiting.
+                WalkControl::SkipChildren
+            }
+            _ => WalkControl::Continue,
+        });
+        vars
+    }
+
+    /// Rewrite every variable reference according to `map`, throughout the tree.
+    pub fn substitute(&mut self, working_set: &mut StateWorkingSet, map: &HashMap<VarId, VarId>) {
+        self.walk_mut(working_set, &mut |expr| {
+            if let Expr::Var(var_id) = &mut expr.expr {
+                if let Some(new_id) = map.get(var_id) {
+                    *var_id = *new_id;
+                }
+            }
+            WalkControl::Continue
+        });
     }
 
     pub fn replace_span(
@@ -431,121 +534,673 @@ impl Expression {
         replaced: Span,
         new_span: Span,
     ) {
-        if replaced.contains_span(self.span) {
-            self.span = new_span;
-        }
+        self.walk_mut(working_set, &mut |expr| {
+            if replaced.contains_span(expr.span) {
+                expr.span = new_span;
+            }
+            if let Expr::Call(call) = &mut expr.expr {
+                if replaced.contains_span(call.head) {
+                    call.head = new_span;
+                }
+            }
+            WalkControl::Continue
+        });
+    }
+
+    /// Constant-fold literal subexpressions in place, bottom-up. Skips folds
+    /// that would hide a runtime error (divide/modulo by zero, integer
+    /// overflow) or change behavior (an operand referencing `$in`).
+    pub fn optimize(&mut self, working_set: &mut StateWorkingSet) {
+        // Fold the children first so that nested literal operations are already
+        // collapsed by the time we look at this node.
         match &mut self.expr {
             Expr::BinaryOp(left, _, right) => {
-                left.replace_span(working_set, replaced, new_span);
-                right.replace_span(working_set, replaced, new_span);
-            }
-            Expr::UnaryNot(expr) => {
-                expr.replace_span(working_set, replaced, new_span);
+                left.optimize(working_set);
+                right.optimize(working_set);
             }
-            Expr::Block(block_id) => {
+            Expr::UnaryNot(expr) => expr.optimize(working_set),
+            Expr::Block(block_id)
+            | Expr::RowCondition(block_id)
+            | Expr::Subexpression(block_id) => {
                 let mut block = working_set.get_block(*block_id).clone();
+                let mut changed = false;
 
                 for pipeline in block.pipelines.iter_mut() {
                     for expr in pipeline.expressions.iter_mut() {
-                        expr.replace_span(working_set, replaced, new_span)
+                        let before = expr.clone();
+                        expr.optimize(working_set);
+                        changed |= *expr != before;
                     }
                 }
 
-                *block_id = working_set.add_block(block);
-            }
-            Expr::Binary(_) => {}
-            Expr::Bool(_) => {}
-            Expr::Call(call) => {
-                if replaced.contains_span(call.head) {
-                    call.head = new_span;
-                }
-                for positional in call.positional_iter_mut() {
-                    positional.replace_span(working_set, replaced, new_span);
-                }
-                for named in call.named_iter_mut() {
-                    if let Some(expr) = &mut named.2 {
-                        expr.replace_span(working_set, replaced, new_span)
-                    }
-                }
-            }
-            Expr::CellPath(_) => {}
-            Expr::DateTime(_) => {}
-            Expr::ExternalCall(head, args) => {
-                head.replace_span(working_set, replaced, new_span);
-                for arg in args {
-                    arg.replace_span(working_set, replaced, new_span)
-                }
-            }
-            Expr::Filepath(_) => {}
-            Expr::Directory(_) => {}
-            Expr::Float(_) => {}
-            Expr::FullCellPath(full_cell_path) => {
-                full_cell_path
-                    .head
-                    .replace_span(working_set, replaced, new_span);
-            }
-            Expr::ImportPattern(_) => {}
-            Expr::Garbage => {}
-            Expr::Nothing => {}
-            Expr::GlobPattern(_) => {}
-            Expr::Int(_) => {}
-            Expr::Keyword(_, _, expr) => expr.replace_span(working_set, replaced, new_span),
-            Expr::List(list) => {
-                for l in list {
-                    l.replace_span(working_set, replaced, new_span)
+                if changed {
+                    *block_id = working_set.add_block(block);
                 }
             }
-            Expr::Operator(_) => {}
             Expr::Range(left, middle, right, ..) => {
                 if let Some(left) = left {
-                    left.replace_span(working_set, replaced, new_span)
+                    left.optimize(working_set);
                 }
                 if let Some(middle) = middle {
-                    middle.replace_span(working_set, replaced, new_span)
+                    middle.optimize(working_set);
                 }
                 if let Some(right) = right {
-                    right.replace_span(working_set, replaced, new_span)
+                    right.optimize(working_set);
                 }
             }
-            Expr::Record(fields) => {
-                for (field_name, field_value) in fields {
-                    field_name.replace_span(working_set, replaced, new_span);
-                    field_value.replace_span(working_set, replaced, new_span);
+            Expr::List(list) => {
+                for l in list {
+                    l.optimize(working_set);
                 }
             }
-            Expr::Signature(_) => {}
-            Expr::String(_) => {}
             Expr::StringInterpolation(items) => {
                 for i in items {
-                    i.replace_span(working_set, replaced, new_span)
+                    i.optimize(working_set);
                 }
             }
-            Expr::RowCondition(block_id) | Expr::Subexpression(block_id) => {
-                let mut block = working_set.get_block(*block_id).clone();
-
-                for pipeline in block.pipelines.iter_mut() {
-                    for expr in pipeline.expressions.iter_mut() {
-                        expr.replace_span(working_set, replaced, new_span)
-                    }
+            Expr::Record(fields) => {
+                for (field_name, field_value) in fields {
+                    field_name.optimize(working_set);
+                    field_value.optimize(working_set);
                 }
-
-                *block_id = working_set.add_block(block);
             }
             Expr::Table(headers, cells) => {
                 for header in headers {
-                    header.replace_span(working_set, replaced, new_span)
+                    header.optimize(working_set);
                 }
-
                 for row in cells {
                     for cell in row.iter_mut() {
-                        cell.replace_span(working_set, replaced, new_span)
+                        cell.optimize(working_set);
+                    }
+                }
+            }
+            Expr::Call(call) => {
+                for positional in call.positional_iter_mut() {
+                    positional.optimize(working_set);
+                }
+                for named in call.named_iter_mut() {
+                    if let Some(expr) = &mut named.2 {
+                        expr.optimize(working_set);
                     }
                 }
             }
+            Expr::ExternalCall(head, args) => {
+                head.optimize(working_set);
+                for arg in args {
+                    arg.optimize(working_set);
+                }
+            }
+            Expr::FullCellPath(full_cell_path) => full_cell_path.head.optimize(working_set),
+            Expr::Keyword(_, _, expr) => expr.optimize(working_set),
+            Expr::ValueWithUnit(expr, _) => expr.optimize(working_set),
+            _ => {}
+        }
 
-            Expr::ValueWithUnit(expr, _) => expr.replace_span(working_set, replaced, new_span),
-            Expr::Var(_) => {}
-            Expr::VarDecl(_) => {}
+        // Now try to fold this node. `$in` operands are left alone so their
+        // runtime value is still substituted in.
+        let folded = match &self.expr {
+            Expr::BinaryOp(left, op, right) => {
+                if left.has_in_variable(working_set) || right.has_in_variable(working_set) {
+                    None
+                } else if let Expr::Operator(operator) = &op.expr {
+                    fold_binary_op(&left.expr, *operator, &right.expr)
+                } else {
+                    None
+                }
+            }
+            Expr::UnaryNot(expr) => {
+                if let Expr::Bool(val) = &expr.expr {
+                    if expr.has_in_variable(working_set) {
+                        None
+                    } else {
+                        Some((Expr::Bool(!*val), Type::Bool))
+                    }
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        };
+
+        if let Some((expr, ty)) = folded {
+            self.expr = expr;
+            self.ty = ty;
+        }
+    }
+
+    /// The innermost expression whose span contains the byte `offset`, or
+    /// `None` when `offset` falls outside `self`.
+    pub fn expression_at<'a>(
+        &'a self,
+        working_set: &'a StateWorkingSet,
+        offset: usize,
+    ) -> Option<&'a Expression> {
+        if !self.span.contains(offset) {
+            return None;
+        }
+
+        let innermost = match &self.expr {
+            Expr::BinaryOp(left, op, right) => left
+                .expression_at(working_set, offset)
+                .or_else(|| op.expression_at(working_set, offset))
+                .or_else(|| right.expression_at(working_set, offset)),
+            Expr::UnaryNot(expr) => expr.expression_at(working_set, offset),
+            Expr::Block(block_id)
+            | Expr::RowCondition(block_id)
+            | Expr::Subexpression(block_id) => working_set
+                .get_block(*block_id)
+                .pipelines
+                .iter()
+                .flat_map(|pipeline| pipeline.expressions.iter())
+                .find_map(|expr| expr.expression_at(working_set, offset)),
+            Expr::Call(call) => call
+                .positional_iter()
+                .find_map(|positional| positional.expression_at(working_set, offset))
+                .or_else(|| {
+                    call.named_iter().find_map(|named| {
+                        named
+                            .2
+                            .as_ref()
+                            .and_then(|expr| expr.expression_at(working_set, offset))
+                    })
+                }),
+            Expr::ExternalCall(head, args) => head
+                .expression_at(working_set, offset)
+                .or_else(|| args.iter().find_map(|arg| arg.expression_at(working_set, offset))),
+            Expr::FullCellPath(full_cell_path) => {
+                full_cell_path.head.expression_at(working_set, offset)
+            }
+            Expr::Keyword(_, _, expr) => expr.expression_at(working_set, offset),
+            Expr::List(list) => list
+                .iter()
+                .find_map(|item| item.expression_at(working_set, offset)),
+            Expr::StringInterpolation(items) => items
+                .iter()
+                .find_map(|item| item.expression_at(working_set, offset)),
+            Expr::Range(left, middle, right, ..) => left
+                .as_ref()
+                .and_then(|left| left.expression_at(working_set, offset))
+                .or_else(|| {
+                    middle
+                        .as_ref()
+                        .and_then(|middle| middle.expression_at(working_set, offset))
+                })
+                .or_else(|| {
+                    right
+                        .as_ref()
+                        .and_then(|right| right.expression_at(working_set, offset))
+                }),
+            Expr::Record(fields) => fields.iter().find_map(|(field_name, field_value)| {
+                field_name
+                    .expression_at(working_set, offset)
+                    .or_else(|| field_value.expression_at(working_set, offset))
+            }),
+            Expr::Table(headers, cells) => headers
+                .iter()
+                .find_map(|header| header.expression_at(working_set, offset))
+                .or_else(|| {
+                    cells.iter().find_map(|row| {
+                        row.iter()
+                            .find_map(|cell| cell.expression_at(working_set, offset))
+                    })
+                }),
+            Expr::ValueWithUnit(expr, _) => expr.expression_at(working_set, offset),
+            _ => None,
+        };
+
+        Some(innermost.unwrap_or(self))
+    }
+
+    /// The innermost command call whose head span covers the byte `offset`.
+    pub fn call_at<'a>(
+        &'a self,
+        working_set: &'a StateWorkingSet,
+        offset: usize,
+    ) -> Option<&'a Call> {
+        match &self.expression_at(working_set, offset)?.expr {
+            Expr::Call(call) if call.head.contains(offset) => Some(call),
+            _ => None,
+        }
+    }
+}
+
+impl StateWorkingSet<'_> {
+    /// The innermost expression within `block_id`'s body whose span contains
+    /// the byte `offset`.
+    ///
+    /// Takes the top-level block to search rather than scanning every block
+    /// this working set has ever interned: a caller resolving a cursor
+    /// offset (an LSP server, say) already knows which parsed file/buffer —
+    /// and so which top-level `block_id` — the offset belongs to. Scanning
+    /// `0..self.num_blocks()` instead would redundantly re-descend into
+    /// nested blocks a second time (once directly, once again as someone
+    /// else's `block_id` in the table), which is strictly worse than just
+    /// asking `Expression::expression_at` to walk the one relevant block.
+    pub fn expression_at(&self, block_id: BlockId, offset: usize) -> Option<&Expression> {
+        self.get_block(block_id)
+            .pipelines
+            .iter()
+            .flat_map(|pipeline| pipeline.expressions.iter())
+            .find_map(|expr| expr.expression_at(self, offset))
+    }
+}
+
+/// Fold a binary operation on two literal operands, or `None` when folding
+/// would hide a runtime error or the operand types do not line up.
+fn fold_binary_op(left: &Expr, op: Operator, right: &Expr) -> Option<(Expr, Type)> {
+    match (left, right) {
+        (Expr::Int(left), Expr::Int(right)) => fold_int(*left, op, *right),
+        (Expr::Float(left), Expr::Float(right)) => fold_float(*left, op, *right),
+        (Expr::Bool(left), Expr::Bool(right)) => fold_bool(*left, op, *right),
+        (Expr::String(left), Expr::String(right)) => fold_string(left, op, right),
+        _ => None,
+    }
+}
+
+fn fold_int(left: i64, op: Operator, right: i64) -> Option<(Expr, Type)> {
+    let int = |val: i64| Some((Expr::Int(val), Type::Int));
+    let boolean = |val: bool| Some((Expr::Bool(val), Type::Bool));
+
+    match op {
+        Operator::Plus => int(left.checked_add(right)?),
+        Operator::Minus => int(left.checked_sub(right)?),
+        Operator::Multiply => int(left.checked_mul(right)?),
+        Operator::Divide => {
+            if right == 0 {
+                None
+            } else if left.checked_rem(right)? == 0 {
+                int(left.checked_div(right)?)
+            } else {
+                Some((Expr::Float(left as f64 / right as f64), Type::Float))
+            }
+        }
+        Operator::FloorDivision => {
+            if right == 0 {
+                return None;
+            }
+            let quotient = left.checked_div(right)?;
+            let remainder = left.checked_rem(right)?;
+            // `checked_div` truncates towards zero; nudge down to floor when the
+            // operands have opposite signs and the division was not exact.
+            let quotient = if remainder != 0 && (remainder < 0) != (right < 0) {
+                quotient.checked_sub(1)?
+            } else {
+                quotient
+            };
+            int(quotient)
+        }
+        Operator::Modulo => {
+            if right == 0 {
+                None
+            } else {
+                int(left.checked_rem(right)?)
+            }
         }
+        Operator::Pow => {
+            if (0..=u32::MAX as i64).contains(&right) {
+                int(left.checked_pow(right as u32)?)
+            } else {
+                None
+            }
+        }
+        Operator::LessThan => boolean(left < right),
+        Operator::LessThanOrEqual => boolean(left <= right),
+        Operator::GreaterThan => boolean(left > right),
+        Operator::GreaterThanOrEqual => boolean(left >= right),
+        Operator::Equal => boolean(left == right),
+        Operator::NotEqual => boolean(left != right),
+        _ => None,
+    }
+}
+
+fn fold_float(left: f64, op: Operator, right: f64) -> Option<(Expr, Type)> {
+    let float = |val: f64| Some((Expr::Float(val), Type::Float));
+    let boolean = |val: bool| Some((Expr::Bool(val), Type::Bool));
+
+    match op {
+        Operator::Plus => float(left + right),
+        Operator::Minus => float(left - right),
+        Operator::Multiply => float(left * right),
+        Operator::Divide => {
+            if right == 0.0 {
+                None
+            } else {
+                float(left / right)
+            }
+        }
+        Operator::FloorDivision => {
+            if right == 0.0 {
+                None
+            } else {
+                float((left / right).floor())
+            }
+        }
+        Operator::Modulo => {
+            if right == 0.0 {
+                None
+            } else {
+                float(left % right)
+            }
+        }
+        Operator::Pow => float(left.powf(right)),
+        Operator::LessThan => boolean(left < right),
+        Operator::LessThanOrEqual => boolean(left <= right),
+        Operator::GreaterThan => boolean(left > right),
+        Operator::GreaterThanOrEqual => boolean(left >= right),
+        Operator::Equal => boolean(left == right),
+        Operator::NotEqual => boolean(left != right),
+        _ => None,
+    }
+}
+
+fn fold_bool(left: bool, op: Operator, right: bool) -> Option<(Expr, Type)> {
+    let boolean = |val: bool| Some((Expr::Bool(val), Type::Bool));
+
+    match op {
+        Operator::And => boolean(left && right),
+        Operator::Or => boolean(left || right),
+        Operator::Equal => boolean(left == right),
+        Operator::NotEqual => boolean(left != right),
+        _ => None,
+    }
+}
+
+fn fold_string(left: &str, op: Operator, right: &str) -> Option<(Expr, Type)> {
+    let boolean = |val: bool| Some((Expr::Bool(val), Type::Bool));
+
+    match op {
+        Operator::Plus => Some((Expr::String(format!("{left}{right}")), Type::String)),
+        Operator::StartsWith => boolean(left.starts_with(right)),
+        Operator::EndsWith => boolean(left.ends_with(right)),
+        Operator::Equal => boolean(left == right),
+        Operator::NotEqual => boolean(left != right),
+        Operator::LessThan => boolean(left < right),
+        Operator::LessThanOrEqual => boolean(left <= right),
+        Operator::GreaterThan => boolean(left > right),
+        Operator::GreaterThanOrEqual => boolean(left >= right),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::{Block, Pipeline};
+    use crate::engine::EngineState;
+
+    fn int_expr(val: i64) -> Expression {
+        Expression {
+            expr: Expr::Int(val),
+            span: Span::test_data(),
+            ty: Type::Int,
+            custom_completion: None,
+        }
+    }
+
+    fn in_var_expr() -> Expression {
+        Expression {
+            expr: Expr::Var(IN_VARIABLE_ID),
+            span: Span::test_data(),
+            ty: Type::Any,
+            custom_completion: None,
+        }
+    }
+
+    fn binary_op(left: Expression, op: Operator, right: Expression) -> Expression {
+        let op_expr = Expression {
+            expr: Expr::Operator(op),
+            span: Span::test_data(),
+            ty: Type::Any,
+            custom_completion: None,
+        };
+        Expression {
+            expr: Expr::BinaryOp(Box::new(left), Box::new(op_expr), Box::new(right)),
+            span: Span::test_data(),
+            ty: Type::Any,
+            custom_completion: None,
+        }
+    }
+
+    fn var_expr(var_id: VarId) -> Expression {
+        Expression {
+            expr: Expr::Var(var_id),
+            span: Span::test_data(),
+            ty: Type::Any,
+            custom_completion: None,
+        }
+    }
+
+    fn block_expr(
+        working_set: &mut StateWorkingSet,
+        captures: Vec<VarId>,
+        body: Expression,
+    ) -> Expression {
+        let block_id = working_set.add_block(Block {
+            pipelines: vec![Pipeline::from_vec(vec![body])],
+            captures,
+            ..Default::default()
+        });
+        Expression {
+            expr: Expr::Block(block_id),
+            span: Span::test_data(),
+            ty: Type::Any,
+            custom_completion: None,
+        }
+    }
+
+    #[test]
+    fn free_variables_still_sees_siblings_after_a_block() {
+        let engine_state = EngineState::new();
+        let mut working_set = StateWorkingSet::new(&engine_state);
+
+        let inner_var: VarId = 1;
+        let outer_var: VarId = 2;
+
+        let block = block_expr(&mut working_set, vec![inner_var], var_expr(inner_var));
+        let sibling = var_expr(outer_var);
+
+        // A block followed by another expression, e.g. a closure argument
+        // followed by a plain variable argument in a call's positional list.
+        let list = Expression {
+            expr: Expr::List(vec![block, sibling]),
+            span: Span::test_data(),
+            ty: Type::List(Box::new(Type::Any)),
+            custom_completion: None,
+        };
+
+        let vars = list.free_variables(&working_set);
+
+        assert!(vars.contains(&inner_var));
+        assert!(vars.contains(&outer_var));
+    }
+
+    #[test]
+    fn substitute_rewrites_nested_block_without_minting_a_new_block_id() {
+        let engine_state = EngineState::new();
+        let mut working_set = StateWorkingSet::new(&engine_state);
+
+        let inner_var: VarId = 1;
+        let renamed_var: VarId = 2;
+
+        let mut expr = block_expr(&mut working_set, vec![inner_var], var_expr(inner_var));
+        let block_id_before = expr.as_block().expect("expression is a block");
+
+        let mut map = HashMap::new();
+        map.insert(inner_var, renamed_var);
+        expr.substitute(&mut working_set, &map);
+
+        let block_id_after = expr.as_block().expect("expression is still a block");
+        assert_eq!(block_id_before, block_id_after);
+
+        let block = working_set.get_block(block_id_after);
+        assert_eq!(block.captures, vec![renamed_var]);
+        match &block.pipelines[0].expressions[0].expr {
+            Expr::Var(id) => assert_eq!(*id, renamed_var),
+            other => panic!("expected Expr::Var, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn expression_at_finds_the_innermost_node() {
+        let engine_state = EngineState::new();
+        let working_set = StateWorkingSet::new(&engine_state);
+
+        let left = Expression {
+            expr: Expr::Int(1),
+            span: Span::new(0, 1),
+            ty: Type::Int,
+            custom_completion: None,
+        };
+        let op = Expression {
+            expr: Expr::Operator(Operator::Plus),
+            span: Span::new(2, 3),
+            ty: Type::Any,
+            custom_completion: None,
+        };
+        let right = Expression {
+            expr: Expr::Int(2),
+            span: Span::new(4, 5),
+            ty: Type::Int,
+            custom_completion: None,
+        };
+        let expr = Expression {
+            expr: Expr::BinaryOp(Box::new(left), Box::new(op), Box::new(right)),
+            span: Span::new(0, 5),
+            ty: Type::Int,
+            custom_completion: None,
+        };
+
+        let found = expr.expression_at(&working_set, 4).map(|e| e.expr.clone());
+        assert_eq!(found, Some(Expr::Int(2)));
+
+        assert!(expr.expression_at(&working_set, 10).is_none());
+    }
+
+    #[test]
+    fn state_working_set_expression_at_searches_the_given_block() {
+        let engine_state = EngineState::new();
+        let mut working_set = StateWorkingSet::new(&engine_state);
+
+        let body = Expression {
+            expr: Expr::Int(7),
+            span: Span::new(10, 11),
+            ty: Type::Int,
+            custom_completion: None,
+        };
+        let wrapped = block_expr(&mut working_set, vec![], body);
+        let block_id = wrapped.as_block().expect("expression is a block");
+
+        let found = working_set
+            .expression_at(block_id, 10)
+            .map(|e| e.expr.clone());
+        assert_eq!(found, Some(Expr::Int(7)));
+
+        assert!(working_set.expression_at(block_id, 0).is_none());
+    }
+
+    #[test]
+    fn optimize_folds_plain_literals() {
+        let engine_state = EngineState::new();
+        let mut working_set = StateWorkingSet::new(&engine_state);
+
+        let mut expr = binary_op(int_expr(2), Operator::Plus, int_expr(3));
+        expr.optimize(&mut working_set);
+
+        assert_eq!(expr.expr, Expr::Int(5));
+    }
+
+    #[test]
+    fn optimize_skips_integer_divide_by_zero() {
+        let engine_state = EngineState::new();
+        let mut working_set = StateWorkingSet::new(&engine_state);
+
+        let mut expr = binary_op(int_expr(1), Operator::Divide, int_expr(0));
+        expr.optimize(&mut working_set);
+
+        assert!(matches!(expr.expr, Expr::BinaryOp(..)));
+    }
+
+    #[test]
+    fn optimize_skips_integer_overflow() {
+        let engine_state = EngineState::new();
+        let mut working_set = StateWorkingSet::new(&engine_state);
+
+        let mut expr = binary_op(int_expr(i64::MAX), Operator::Plus, int_expr(1));
+        expr.optimize(&mut working_set);
+
+        assert!(matches!(expr.expr, Expr::BinaryOp(..)));
+    }
+
+    #[test]
+    fn optimize_skips_operand_referencing_in_variable() {
+        let engine_state = EngineState::new();
+        let mut working_set = StateWorkingSet::new(&engine_state);
+
+        let mut expr = binary_op(in_var_expr(), Operator::Plus, int_expr(1));
+        expr.optimize(&mut working_set);
+
+        assert!(matches!(expr.expr, Expr::BinaryOp(..)));
+    }
+
+    #[test]
+    fn walk_stop_aborts_the_rest_of_the_walk() {
+        let engine_state = EngineState::new();
+        let working_set = StateWorkingSet::new(&engine_state);
+
+        let list = Expression {
+            expr: Expr::List(vec![int_expr(1), int_expr(2), int_expr(3)]),
+            span: Span::test_data(),
+            ty: Type::List(Box::new(Type::Int)),
+            custom_completion: None,
+        };
+
+        let mut visited = vec![];
+        let complete = list.walk(&working_set, &mut |expr| {
+            if let Expr::Int(val) = expr.expr {
+                visited.push(val);
+                if val == 1 {
+                    WalkControl::Stop
+                } else {
+                    WalkControl::Continue
+                }
+            } else {
+                WalkControl::Continue
+            }
+        });
+
+        assert!(!complete);
+        assert_eq!(visited, vec![1]);
+    }
+
+    #[test]
+    fn walk_skip_children_only_prunes_that_node() {
+        let engine_state = EngineState::new();
+        let working_set = StateWorkingSet::new(&engine_state);
+
+        let list = Expression {
+            expr: Expr::List(vec![int_expr(1), int_expr(2), int_expr(3)]),
+            span: Span::test_data(),
+            ty: Type::List(Box::new(Type::Int)),
+            custom_completion: None,
+        };
+
+        let mut visited = vec![];
+        let complete = list.walk(&working_set, &mut |expr| {
+            if let Expr::Int(val) = expr.expr {
+                visited.push(val);
+                if val == 1 {
+                    WalkControl::SkipChildren
+                } else {
+                    WalkControl::Continue
+                }
+            } else {
+                WalkControl::Continue
+            }
+        });
+
+        // Unlike `Stop`, pruning one node's children does not abort the walk:
+        // its siblings are still visited and the walk still completes.
+        assert!(complete);
+        assert_eq!(visited, vec![1, 2, 3]);
     }
 }